@@ -0,0 +1,150 @@
+use base58::ToBase58;
+use sha2::{Digest, Sha256};
+
+/// Sliding window the rolling hash is computed over when looking for a cut
+/// point.
+const WINDOW_SIZE: usize = 48;
+
+/// Chunks are never produced smaller than this (except a trailing remainder).
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunk boundaries are placed so chunks average roughly this size.
+pub const TARGET_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks are always cut by this size, even without a rolling-hash match.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Each byte shifts the hash left by one, so hash bit `k` depends only on the
+/// last `k + 1` bytes. Testing the 16 bits that end at bit `WINDOW_SIZE - 1`
+/// makes each cut decision depend on the last `WINDOW_SIZE` bytes.
+const CUT_MASK: u64 = ((TARGET_CHUNK_SIZE - 1) as u64) << (WINDOW_SIZE - 16);
+
+/// Splits `data` into content-defined chunks: cut points are chosen by
+/// content rather than fixed offsets, so an edit only reshapes the chunks
+/// touching it.
+pub fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    if data.len() <= MIN_CHUNK_SIZE {
+        return vec![data];
+    }
+
+    let gear = gear_table();
+    let mut boundaries = Vec::new();
+    let mut start = 0;
+    let mut hash: u64 = 0;
+
+    for i in 0..data.len() {
+        hash = (hash << 1).wrapping_add(gear[data[i] as usize]);
+
+        let chunk_len = i + 1 - start;
+        let past_window = i + 1 >= start + WINDOW_SIZE;
+        let at_cut_point = past_window && (hash & CUT_MASK) == 0;
+        let is_last_byte = i == data.len() - 1;
+
+        if chunk_len >= MAX_CHUNK_SIZE || is_last_byte || (chunk_len >= MIN_CHUNK_SIZE && at_cut_point) {
+            boundaries.push(&data[start..=i]);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+
+    boundaries
+}
+
+/// Builds the Merkle root over an ordered list of chunk keys.
+pub fn merkle_root(chunk_keys: &[String]) -> String {
+    let mut level: Vec<Vec<u8>> = chunk_keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+
+    if level.is_empty() {
+        return Sha256::default().result().as_slice().to_base58();
+    }
+
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| {
+            let mut hasher = Sha256::default();
+            hasher.input(&pair[0]);
+            hasher.input(pair.get(1).unwrap_or(&pair[0]));
+            hasher.result().to_vec()
+        }).collect();
+    }
+
+    level[0].to_base58()
+}
+
+/// Pseudo-random constants, one per byte value, used to roll the cut-point
+/// hash a byte at a time.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    for (i, slot) in table.iter_mut().enumerate() {
+        *slot = splitmix64(i as u64 + 1);
+    }
+    table
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_reassemble_to_original() {
+        let data: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+        let reassembled: Vec<u8> = chunks(&data).into_iter().flatten().cloned().collect();
+        assert_eq!(reassembled, data);
+    }
+
+    #[test]
+    fn chunks_of_empty_data_is_empty() {
+        assert!(chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn small_data_is_a_single_chunk() {
+        let data = vec![1u8; MIN_CHUNK_SIZE - 1];
+        assert_eq!(chunks(&data), vec![&data[..]]);
+    }
+
+    #[test]
+    fn no_chunk_exceeds_max_size() {
+        let data: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(chunks(&data).iter().all(|chunk| chunk.len() <= MAX_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn inserting_bytes_only_reshapes_chunks_near_the_edit() {
+        let original: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+        let mut edited = original.clone();
+        edited.splice(250_000..250_000, vec![9u8; 37]);
+
+        let original_chunks = chunks(&original);
+        let edited_chunks = chunks(&edited);
+
+        let unchanged = original_chunks.iter()
+            .filter(|chunk| edited_chunks.contains(chunk))
+            .count();
+        assert!(unchanged >= original_chunks.len() - 2);
+    }
+
+    #[test]
+    fn merkle_root_is_deterministic_and_order_sensitive() {
+        let keys = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        let reordered = vec!["c".to_owned(), "b".to_owned(), "a".to_owned()];
+
+        assert_eq!(merkle_root(&keys), merkle_root(&keys));
+        assert_ne!(merkle_root(&keys), merkle_root(&reordered));
+    }
+
+    #[test]
+    fn merkle_root_of_empty_keys_is_stable() {
+        assert_eq!(merkle_root(&[]), merkle_root(&[]));
+    }
+}
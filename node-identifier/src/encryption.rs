@@ -0,0 +1,355 @@
+use std::env;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, NewAead};
+use failure::Error;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, GenerateDataKeyRequest, Kms, KmsClient};
+use sqs_lambda::{PayloadDecoder, ZstdProtoDecoder};
+
+use io_util::read_full;
+
+const NONCE_LEN: usize = 12;
+const HEADER_VERSION_STATIC: u8 = 1;
+const HEADER_VERSION_KMS: u8 = 2;
+
+/// Plaintext bytes per frame `EncryptingReader` encrypts independently.
+const FRAME_PLAINTEXT_SIZE: usize = 64 * 1024;
+
+/// The AES-256-GCM key uploaded subgraphs are encrypted under.
+pub enum EncryptionKey {
+    Static([u8; 32]),
+    Kms {
+        client: KmsClient,
+        key_id: String,
+        cached: Mutex<Option<([u8; 32], Vec<u8>)>>,
+    },
+}
+
+impl EncryptionKey {
+    /// Reads `SUBGRAPH_ENCRYPTION_KEY` or `SUBGRAPH_ENCRYPTION_KMS_KEY_ID`.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        if let Ok(encoded) = env::var("SUBGRAPH_ENCRYPTION_KEY") {
+            return Ok(Some(EncryptionKey::Static(to_key_array(&base64::decode(&encoded)?)?)));
+        }
+
+        if let Ok(key_id) = env::var("SUBGRAPH_ENCRYPTION_KMS_KEY_ID") {
+            return Ok(Some(EncryptionKey::Kms {
+                client: KmsClient::simple(Region::UsEast1),
+                key_id,
+                cached: Mutex::new(None),
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the plaintext key, and -- for KMS -- the wrapped key to store
+    /// alongside the object. Calls `GenerateDataKey` at most once per
+    /// `EncryptionKey` value.
+    fn data_key(&self) -> Result<([u8; 32], Option<Vec<u8>>), Error> {
+        match self {
+            EncryptionKey::Static(key) => Ok((*key, None)),
+            EncryptionKey::Kms { client, key_id, cached } => {
+                let mut cached = cached.lock().expect("encryption key cache mutex poisoned");
+                if let Some((key, wrapped)) = cached.as_ref() {
+                    return Ok((*key, Some(wrapped.clone())));
+                }
+
+                let response = client.generate_data_key(&GenerateDataKeyRequest {
+                    key_id: key_id.clone(),
+                    key_spec: Some("AES_256".to_owned()),
+                    ..Default::default()
+                }).sync()?;
+
+                let plaintext = response.plaintext
+                    .ok_or_else(|| format_err!("KMS GenerateDataKey returned no plaintext key"))?;
+                let wrapped = response.ciphertext_blob
+                    .ok_or_else(|| format_err!("KMS GenerateDataKey returned no ciphertext_blob"))?;
+                let key = to_key_array(&plaintext)?;
+
+                *cached = Some((key, wrapped.clone()));
+                Ok((key, Some(wrapped)))
+            }
+        }
+    }
+
+    /// Encrypts with AES-256-GCM, prepending a version byte, the KMS-wrapped
+    /// data key when running in KMS mode, and the random nonce.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let (key, wrapped) = self.data_key()?;
+        let cipher = Aes256Gcm::new(key.into());
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_bytes);
+
+        let ciphertext = cipher.encrypt(nonce_bytes[..].into(), plaintext)
+            .map_err(|_| format_err!("AES-256-GCM encryption failed"))?;
+
+        let mut out = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        match wrapped {
+            Some(wrapped) => {
+                out.push(HEADER_VERSION_KMS);
+                out.extend_from_slice(&(wrapped.len() as u32).to_be_bytes());
+                out.extend_from_slice(&wrapped);
+            }
+            None => out.push(HEADER_VERSION_STATIC),
+        }
+        out.extend_from_slice(&nonce_bytes);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Inverse of `encrypt`.
+    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        if encrypted.is_empty() {
+            return Err(format_err!("ciphertext too short to contain header"));
+        }
+
+        let (key, rest) = match encrypted[0] {
+            HEADER_VERSION_STATIC => {
+                let (key, _) = self.data_key()?;
+                (key, &encrypted[1..])
+            }
+            HEADER_VERSION_KMS => {
+                if encrypted.len() < 5 {
+                    return Err(format_err!("truncated KMS-wrapped key header"));
+                }
+                let mut len_bytes = [0u8; 4];
+                len_bytes.copy_from_slice(&encrypted[1..5]);
+                let wrapped_len = u32::from_be_bytes(len_bytes) as usize;
+
+                if encrypted.len() < 5 + wrapped_len {
+                    return Err(format_err!("truncated KMS-wrapped key body"));
+                }
+                let key = self.unwrap_kms_key(&encrypted[5..5 + wrapped_len])?;
+                (key, &encrypted[5 + wrapped_len..])
+            }
+            other => return Err(format_err!("unsupported encryption header version {}", other)),
+        };
+
+        if rest.len() < NONCE_LEN {
+            return Err(format_err!("ciphertext too short to contain nonce"));
+        }
+        let nonce = &rest[..NONCE_LEN];
+        let ciphertext = &rest[NONCE_LEN..];
+
+        let cipher = Aes256Gcm::new(key.into());
+        cipher.decrypt(nonce.into(), ciphertext)
+            .map_err(|_| format_err!("AES-256-GCM decryption failed"))
+    }
+
+    /// Recovers the plaintext data key wrapped in `wrapped` via `kms:Decrypt`.
+    fn unwrap_kms_key(&self, wrapped: &[u8]) -> Result<[u8; 32], Error> {
+        match self {
+            EncryptionKey::Static(_) =>
+                Err(format_err!("payload is KMS-wrapped but SUBGRAPH_ENCRYPTION_KEY is configured")),
+            EncryptionKey::Kms { client, .. } => {
+                let response = client.decrypt(&DecryptRequest {
+                    ciphertext_blob: wrapped.to_vec(),
+                    ..Default::default()
+                }).sync()?;
+
+                let plaintext = response.plaintext
+                    .ok_or_else(|| format_err!("KMS Decrypt returned no plaintext key"))?;
+                to_key_array(&plaintext)
+            }
+        }
+    }
+}
+
+/// Wraps a `Read` of compressed bytes, encrypting them under `key` in
+/// independent, length-prefixed frames as they're produced.
+pub struct EncryptingReader<'a, R> {
+    inner: R,
+    key: &'a EncryptionKey,
+    frame: Vec<u8>,
+    frame_pos: usize,
+    done: bool,
+}
+
+impl<'a, R: Read> EncryptingReader<'a, R> {
+    pub fn new(inner: R, key: &'a EncryptionKey) -> Self {
+        Self { inner, key, frame: Vec::new(), frame_pos: 0, done: false }
+    }
+
+    fn fill_frame(&mut self) -> io::Result<()> {
+        let mut plaintext = vec![0u8; FRAME_PLAINTEXT_SIZE];
+        let n = read_full(&mut self.inner, &mut plaintext)?;
+        if n == 0 {
+            self.done = true;
+            return Ok(());
+        }
+        plaintext.truncate(n);
+
+        let ciphertext = self.key.encrypt(&plaintext)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        self.frame.clear();
+        self.frame.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        self.frame.extend_from_slice(&ciphertext);
+        self.frame_pos = 0;
+        Ok(())
+    }
+}
+
+impl<'a, R: Read> Read for EncryptingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.frame_pos >= self.frame.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill_frame()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+
+        let n = std::cmp::min(buf.len(), self.frame.len() - self.frame_pos);
+        buf[..n].copy_from_slice(&self.frame[self.frame_pos..self.frame_pos + n]);
+        self.frame_pos += n;
+        Ok(n)
+    }
+}
+
+/// Inverse of `EncryptingReader`: reads back the length-prefixed frames it
+/// emits and decrypts each one, returning the concatenated plaintext.
+pub fn decrypt_framed(key: &EncryptionKey, framed: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut plaintext = Vec::new();
+    let mut pos = 0;
+
+    while pos < framed.len() {
+        if framed.len() < pos + 4 {
+            return Err(format_err!("truncated frame length header"));
+        }
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&framed[pos..pos + 4]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        pos += 4;
+
+        if framed.len() < pos + len {
+            return Err(format_err!("truncated frame body"));
+        }
+        plaintext.extend_from_slice(&key.decrypt(&framed[pos..pos + len])?);
+        pos += len;
+    }
+
+    Ok(plaintext)
+}
+
+fn to_key_array(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    if bytes.len() != 32 {
+        return Err(format_err!("encryption key must be 32 bytes, got {}", bytes.len()));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+/// Holds the most recent raw, as-received-from-S3 bytes a decoder has seen,
+/// so a caller downstream of decoding (e.g. an idempotency check) can hash
+/// the bytes as they actually arrived instead of re-encoding the decoded
+/// struct, whose map-field encoding order isn't stable across processes.
+pub type RawPayloadSlot = Arc<Mutex<Option<Vec<u8>>>>;
+
+/// Wraps `ZstdProtoDecoder` with an AES-256-GCM decrypt step so the
+/// retriever can read subgraphs `upload_identified_graphs` encrypted at
+/// rest. With `key` set to `None` it behaves exactly like `ZstdProtoDecoder`.
+pub struct EncryptedZstdProtoDecoder {
+    key: Option<Arc<EncryptionKey>>,
+    inner: ZstdProtoDecoder,
+    last_raw_payload: RawPayloadSlot,
+}
+
+impl EncryptedZstdProtoDecoder {
+    pub fn new(key: Option<Arc<EncryptionKey>>, last_raw_payload: RawPayloadSlot) -> Self {
+        Self { key, inner: ZstdProtoDecoder {}, last_raw_payload }
+    }
+}
+
+impl<T> PayloadDecoder<T> for EncryptedZstdProtoDecoder
+    where ZstdProtoDecoder: PayloadDecoder<T>
+{
+    fn decode(&self, body: Vec<u8>) -> Result<T, Error> {
+        *self.last_raw_payload.lock().expect("raw payload slot mutex poisoned") = Some(body.clone());
+
+        let body = match self.key {
+            Some(ref key) => decrypt_framed(key, &body)?,
+            None => body,
+        };
+        self.inner.decode(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read as _;
+
+    fn static_key() -> EncryptionKey {
+        EncryptionKey::Static([7u8; 32])
+    }
+
+    #[test]
+    fn encrypt_decrypt_roundtrip() {
+        let key = static_key();
+        let plaintext = b"a subgraph goes here";
+        let encrypted = key.encrypt(plaintext).unwrap();
+        assert_eq!(key.decrypt(&encrypted).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_ciphertext() {
+        let key = static_key();
+        let mut encrypted = key.encrypt(b"a subgraph goes here").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0xFF;
+        assert!(key.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_unknown_header_version() {
+        let key = static_key();
+        let encrypted = vec![99u8; 40];
+        assert!(key.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn encrypting_reader_roundtrips_through_decrypt_framed() {
+        let key = static_key();
+        let plaintext = vec![42u8; FRAME_PLAINTEXT_SIZE + 100];
+        let mut reader = EncryptingReader::new(&plaintext[..], &key);
+
+        let mut framed = Vec::new();
+        reader.read_to_end(&mut framed).unwrap();
+
+        assert_eq!(decrypt_framed(&key, &framed).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn encrypting_reader_roundtrips_through_object_store_put_streaming() {
+        use object_store::{LocalObjectStore, ObjectStore};
+        use std::env;
+        use std::fs;
+
+        let base_dir = env::temp_dir()
+            .join("grapl-encryption-test")
+            .join("roundtrip_through_object_store_put_streaming");
+        let _ = fs::remove_dir_all(&base_dir);
+        env::set_var("LOCAL_STORAGE_DIR", &base_dir);
+
+        let key = static_key();
+        let plaintext = vec![42u8; FRAME_PLAINTEXT_SIZE + 100];
+        let mut reader = EncryptingReader::new(&plaintext[..], &key);
+
+        let store = LocalObjectStore::new();
+        store.put_streaming("bucket", "somekey", &mut reader, FRAME_PLAINTEXT_SIZE).unwrap();
+
+        let framed = store.get("bucket", "somekey").unwrap();
+        assert_eq!(decrypt_framed(&key, &framed).unwrap(), plaintext);
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base58::ToBase58;
+use lru_time_cache::LruCache;
+use sha2::{Digest, Sha256};
+
+/// A content-keyed cache of subgraph batches `handle_event` has already
+/// finished processing, so retries of the same S3 payload don't re-run
+/// identification or re-upload an identical identified graph.
+pub struct IdempotencyCache {
+    seen: Mutex<LruCache<String, ()>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(max_count: usize, time_to_live: Duration) -> Self {
+        Self {
+            seen: Mutex::new(LruCache::with_expiry_duration_and_capacity(time_to_live, max_count)),
+        }
+    }
+
+    /// Hashes `payload` and returns its cache key without recording it.
+    pub fn key_for(&self, payload: &[u8]) -> String {
+        let mut hasher = Sha256::default();
+        hasher.input(payload);
+        hasher.result().as_ref().to_base58()
+    }
+
+    /// True if `key` was already marked as completed.
+    pub fn contains(&self, key: &str) -> bool {
+        self.seen.lock().expect("idempotency cache mutex poisoned")
+            .peek(key)
+            .is_some()
+    }
+
+    /// Marks `key` as completed.
+    pub fn mark_complete(&self, key: String) {
+        self.seen.lock().expect("idempotency cache mutex poisoned")
+            .insert(key, ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_for_is_deterministic_and_content_addressed() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        assert_eq!(cache.key_for(b"payload"), cache.key_for(b"payload"));
+        assert_ne!(cache.key_for(b"payload"), cache.key_for(b"other"));
+    }
+
+    #[test]
+    fn contains_reflects_mark_complete() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        let key = cache.key_for(b"payload");
+
+        assert!(!cache.contains(&key));
+        cache.mark_complete(key.clone());
+        assert!(cache.contains(&key));
+    }
+
+    #[test]
+    fn unmarked_key_is_not_contained() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        assert!(!cache.contains(&cache.key_for(b"never marked")));
+    }
+}
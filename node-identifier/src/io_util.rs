@@ -0,0 +1,13 @@
+use std::io::{self, Read};
+
+/// Reads from `reader` until `buf` is full or the stream is exhausted.
+pub(crate) fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,352 @@
+use std::env;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use futures::future::Future;
+use futures::Stream;
+use rusoto_core::Region;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, ListObjectsV2Request,
+    PutObjectRequest, S3, S3Client, UploadPartRequest,
+};
+use tokio::runtime::Runtime;
+
+use io_util::read_full;
+
+pub type SharedRuntime = Arc<Mutex<Runtime>>;
+
+/// S3's own minimum part size for all but the last part of a multipart upload.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+pub trait ObjectStore: Send + Sync {
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), Error>;
+    fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>, Error>;
+    fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// Pulls bytes from `body` in roughly `part_size` chunks instead of
+    /// requiring the whole payload already buffered, so a caller can stream
+    /// compression output straight through to storage without pinning the
+    /// whole compressed object in memory. `part_size` is only a hint;
+    /// backends without multipart support fall back to reading the whole
+    /// stream into memory and calling `put`.
+    fn put_streaming(&self, bucket: &str, key: &str, body: &mut dyn Read, part_size: usize) -> Result<(), Error> {
+        let _ = part_size;
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf)?;
+        self.put(bucket, key, buf)
+    }
+
+    fn exists(&self, bucket: &str, key: &str) -> Result<bool, Error> {
+        Ok(self.list(bucket, key)?.iter().any(|existing| existing == key))
+    }
+}
+
+/// Reads `STORAGE_BACKEND` (`s3` (default), `gcs`, `azure`, or `local`).
+pub fn object_store_from_env(runtime: SharedRuntime) -> Result<Box<dyn ObjectStore>, Error> {
+    let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "s3".to_owned());
+
+    match backend.as_str() {
+        "s3" => Ok(Box::new(S3ObjectStore::new(Region::UsEast1, runtime))),
+        "gcs" => Ok(Box::new(GcsObjectStore::new()?)),
+        "azure" => Ok(Box::new(AzureObjectStore::new()?)),
+        "local" => Ok(Box::new(LocalObjectStore::new())),
+        other => Err(format_err!("unknown STORAGE_BACKEND: {}", other)),
+    }
+}
+
+pub struct S3ObjectStore {
+    client: S3Client,
+    runtime: SharedRuntime,
+}
+
+impl S3ObjectStore {
+    pub fn new(region: Region, runtime: SharedRuntime) -> Self {
+        Self { client: S3Client::simple(region), runtime }
+    }
+
+    /// Blocks the calling thread until `future` resolves, against the
+    /// shared runtime instead of a fresh one per call. This is still a
+    /// synchronous call from the caller's perspective -- it amortizes
+    /// executor setup, it doesn't make `S3ObjectStore`'s methods `async`.
+    fn block_on<T, E>(&self, future: impl Future<Item = T, Error = E> + Send + 'static) -> Result<T, E>
+        where T: Send + 'static, E: Send + 'static
+    {
+        self.runtime.lock().expect("runtime mutex poisoned").block_on(future)
+    }
+
+    /// Uploads from `body` as a multipart object, reading one part of up to
+    /// `part_size` bytes at a time so the whole payload is never buffered at
+    /// once, aborting the upload on any error so no orphaned parts are left
+    /// behind.
+    fn put_multipart_streaming(&self, bucket: &str, key: &str, body: &mut dyn Read, part_size: usize) -> Result<(), Error> {
+        let created = self.block_on(self.client.create_multipart_upload(&CreateMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        }))?;
+
+        let upload_id = created.upload_id
+            .ok_or_else(|| format_err!("no upload_id for {}/{}", bucket, key))?;
+
+        let result = self.upload_parts_streaming(bucket, key, &upload_id, body, part_size);
+
+        if result.is_err() {
+            warn!("aborting multipart upload for {}/{}", bucket, key);
+            let _ = self.block_on(self.client.abort_multipart_upload(&AbortMultipartUploadRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                upload_id,
+                ..Default::default()
+            }));
+        }
+
+        result
+    }
+
+    fn upload_parts_streaming(&self, bucket: &str, key: &str, upload_id: &str, body: &mut dyn Read, part_size: usize) -> Result<(), Error> {
+        let mut completed_parts = Vec::new();
+        let mut part_number = 0i64;
+
+        loop {
+            let mut part = vec![0u8; part_size];
+            let len = read_full(body, &mut part)?;
+            if len == 0 {
+                break;
+            }
+            part.truncate(len);
+            part_number += 1;
+
+            let uploaded = self.block_on(self.client.upload_part(&UploadPartRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                part_number,
+                body: Some(part),
+                ..Default::default()
+            }))?;
+
+            let e_tag = uploaded.e_tag
+                .ok_or_else(|| format_err!("no ETag for {}/{} part {}", bucket, key, part_number))?;
+            completed_parts.push(CompletedPart {
+                e_tag: Some(e_tag),
+                part_number: Some(part_number),
+            });
+        }
+
+        self.block_on(self.client.complete_multipart_upload(&CompleteMultipartUploadRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            multipart_upload: Some(CompletedMultipartUpload {
+                parts: Some(completed_parts),
+            }),
+            ..Default::default()
+        }))?;
+
+        Ok(())
+    }
+}
+
+impl ObjectStore for S3ObjectStore {
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        self.block_on(self.client.put_object(&PutObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            body: Some(body),
+            ..Default::default()
+        }))?;
+        Ok(())
+    }
+
+    fn put_streaming(&self, bucket: &str, key: &str, body: &mut dyn Read, part_size: usize) -> Result<(), Error> {
+        let mut first = vec![0u8; part_size];
+        let first_len = read_full(body, &mut first)?;
+        first.truncate(first_len);
+
+        let mut second = vec![0u8; part_size];
+        let second_len = read_full(body, &mut second)?;
+
+        if second_len == 0 {
+            return self.put(bucket, key, first);
+        }
+        second.truncate(second_len);
+
+        let mut combined = Cursor::new(first).chain(Cursor::new(second)).chain(body);
+        self.put_multipart_streaming(bucket, key, &mut combined, part_size)
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>, Error> {
+        let output = self.block_on(self.client.get_object(&GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        }))?;
+
+        let body = output.body.ok_or_else(|| format_err!("no body for {}/{}", bucket, key))?;
+        let body = self.block_on(body.concat2())?;
+        Ok(body.to_vec())
+    }
+
+    fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, Error> {
+        let output = self.block_on(self.client.list_objects_v2(&ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            ..Default::default()
+        }))?;
+
+        Ok(output.contents.unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| object.key)
+            .collect())
+    }
+}
+
+pub struct GcsObjectStore {
+    client: cloud_storage::Client,
+}
+
+impl GcsObjectStore {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self { client: cloud_storage::Client::new()? })
+    }
+}
+
+impl ObjectStore for GcsObjectStore {
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        self.client.object()
+            .create(bucket, body, key, "application/octet-stream")?;
+        Ok(())
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>, Error> {
+        Ok(self.client.object().download(bucket, key)?)
+    }
+
+    fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, Error> {
+        let objects = self.client.object().list_prefix(bucket, prefix)?;
+        Ok(objects.into_iter().map(|object| object.name).collect())
+    }
+}
+
+pub struct AzureObjectStore {
+    client: azure_storage_blobs::BlobClient,
+}
+
+impl AzureObjectStore {
+    pub fn new() -> Result<Self, Error> {
+        let account = env::var("AZURE_STORAGE_ACCOUNT")?;
+        let key = env::var("AZURE_STORAGE_KEY")?;
+        Ok(Self { client: azure_storage_blobs::BlobClient::new(account, key)? })
+    }
+}
+
+impl ObjectStore for AzureObjectStore {
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        self.client.put_block_blob(bucket, key, body)?;
+        Ok(())
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>, Error> {
+        Ok(self.client.get_blob(bucket, key)?)
+    }
+
+    fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self.client.list_blobs(bucket, prefix)?)
+    }
+}
+
+/// Writes objects under `base_dir/<bucket>/<key>`, for local development
+/// and tests that shouldn't need real S3 stubbing.
+pub struct LocalObjectStore {
+    base_dir: PathBuf,
+}
+
+impl LocalObjectStore {
+    pub fn new() -> Self {
+        let base_dir = env::var("LOCAL_STORAGE_DIR")
+            .unwrap_or_else(|_| "/tmp/grapl-object-store".to_owned());
+        Self { base_dir: PathBuf::from(base_dir) }
+    }
+
+    fn path_for(&self, bucket: &str, key: &str) -> PathBuf {
+        self.base_dir.join(bucket).join(key)
+    }
+}
+
+impl ObjectStore for LocalObjectStore {
+    fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<(), Error> {
+        let path = self.path_for(bucket, key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, body)?;
+        Ok(())
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>, Error> {
+        let mut file = fs::File::open(self.path_for(bucket, key))?;
+        let mut body = Vec::new();
+        file.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, Error> {
+        let dir = self.base_dir.join(bucket);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let key = entry.file_name().into_string()
+                .map_err(|_| format_err!("non-utf8 key in local object store"))?;
+            if key.starts_with(prefix) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> LocalObjectStore {
+        let base_dir = env::temp_dir().join("grapl-object-store-test").join(name);
+        let _ = fs::remove_dir_all(&base_dir);
+        LocalObjectStore { base_dir }
+    }
+
+    #[test]
+    fn put_get_roundtrip() {
+        let store = store("put_get_roundtrip");
+        store.put("bucket", "somekey", b"hello world".to_vec()).unwrap();
+        assert_eq!(store.get("bucket", "somekey").unwrap(), b"hello world".to_vec());
+    }
+
+    #[test]
+    fn list_filters_by_prefix() {
+        let store = store("list_filters_by_prefix");
+        store.put("bucket", "day1-a", b"a".to_vec()).unwrap();
+        store.put("bucket", "day1-b", b"b".to_vec()).unwrap();
+        store.put("bucket", "day2-c", b"c".to_vec()).unwrap();
+
+        let mut keys = store.list("bucket", "day1-").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["day1-a".to_owned(), "day1-b".to_owned()]);
+    }
+
+    #[test]
+    fn exists_reflects_prior_put() {
+        let store = store("exists_reflects_prior_put");
+        assert!(!store.exists("bucket", "somekey").unwrap());
+        store.put("bucket", "somekey", b"hello".to_vec()).unwrap();
+        assert!(store.exists("bucket", "somekey").unwrap());
+    }
+}